@@ -2,17 +2,100 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use acme_client::Directory;
+use acme_client::{Account, Directory};
 use acme_client::error::Error as AcmeError;
+use openssl::pkey::PKey;
+use rcgen::{CertificateParams, KeyPair, PKCS_ECDSA_P256_SHA256};
 use types::{Discovered, NameAndToken, ServerInfo};
 use url::percent_encoding::{percent_encode, QUERY_ENCODE_SET};
 use reqwest::{Client, Error as ReqwestError, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+use serde_json;
+use serde_json::json;
+use serde_json::Value as JsonValue;
+use std::cmp;
 use std::convert::From;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use time::OffsetDateTime;
+use trust_dns_resolver::Resolver;
+use trust_dns_resolver::config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig,
+                                  ResolverOpts};
+use trust_dns_resolver::proto::rr::RecordType;
+use x509_parser::pem::parse_x509_pem;
+
+// A certificate is renewed once fewer than this many days remain before it expires.
+pub const DEFAULT_RENEW_BEFORE_DAYS: i64 = 30;
+
+// How long to wait for a freshly published TXT record to propagate before giving up.
+pub const DEFAULT_PROPAGATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Persisted across runs so the same ACME account (and its rate limits) is reused instead of
+// registering a new one on every issuance.
+#[derive(Serialize, Deserialize)]
+struct AccountConfig {
+    private_key_pem: String,
+    registration_url: String,
+}
+
+const LETS_ENCRYPT_STAGING_DIRECTORY_URL: &str = "https://acme-staging-v02.api.letsencrypt.\
+                                                   org/directory";
+
+// Network timeout applied to every request made by the shared http client.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How many times a request is retried after a network error or a 5xx response.
+const MAX_RETRIES: u32 = 3;
+
+/// Selects which ACME directory `lets_encrypt*` talks to.
+#[derive(Clone)]
+pub enum AcmeEndpoint {
+    Production,
+    Staging,
+    Custom(String),
+}
+
+impl AcmeEndpoint {
+    fn directory(&self) -> Result<Directory, TunnelClientError> {
+        match *self {
+            AcmeEndpoint::Production => Ok(Directory::lets_encrypt()?),
+            AcmeEndpoint::Staging => Ok(Directory::from_url(LETS_ENCRYPT_STAGING_DIRECTORY_URL)?),
+            AcmeEndpoint::Custom(ref url) => Ok(Directory::from_url(url)?),
+        }
+    }
+
+    // Name of the account file this endpoint's account is persisted under, so switching
+    // between e.g. staging and production never reuses the other's account kid (each ACME
+    // server only recognizes the registrations it itself issued).
+    fn account_filename(&self) -> String {
+        match *self {
+            AcmeEndpoint::Production => "account-production.json".to_owned(),
+            AcmeEndpoint::Staging => "account-staging.json".to_owned(),
+            AcmeEndpoint::Custom(ref url) => format!("account-custom-{}.json", slugify(url)),
+        }
+    }
+}
+
+// Turns an arbitrary string into a filename-safe slug by keeping alphanumerics and replacing
+// every other character with `_`.
+fn slugify(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
 
 pub struct TunnelClient {
     pub tunnel_url: String,
     pub token: Option<String>,
+    client: Client,
+    acme_endpoint: AcmeEndpoint,
+    contact_email: Option<String>,
 }
 
 #[derive(Debug)]
@@ -21,6 +104,8 @@ pub enum TunnelClientError {
     NoToken,
     NoChallenge,
     BadRequest,
+    PropagationTimeout,
+    Certificate(String),
     Other(String),
     Acme(AcmeError),
 }
@@ -41,9 +126,85 @@ impl From<ReqwestError> for TunnelClientError {
     }
 }
 
+// Issues a GET against `url` through `client`. When `retry` is set, network errors and 5xx
+// responses are retried with a capped exponential backoff instead of failing on the first
+// blip; non-idempotent endpoints (e.g. subscribing, which mints a new name/token pair) must
+// pass `false` so a slow response can't be mistaken for a failure and resent.
+fn get_with_retry(client: &Client, url: &str, retry: bool) -> Result<reqwest::Response, ReqwestError> {
+    let max_retries = if retry { MAX_RETRIES } else { 0 };
+    let mut attempt = 0;
+    loop {
+        match client.get(url).send() {
+            Ok(response) => {
+                if response.status().is_server_error() && attempt < max_retries {
+                    attempt += 1;
+                    warn!("GET {} returned {}, retrying ({}/{})",
+                          url,
+                          response.status(),
+                          attempt,
+                          max_retries);
+                    thread::sleep(Duration::from_secs(2u64.pow(attempt)));
+                    continue;
+                }
+                return Ok(response);
+            }
+            Err(err) => {
+                if attempt < max_retries {
+                    attempt += 1;
+                    warn!("GET {} failed ({}), retrying ({}/{})", url, err, attempt, max_retries);
+                    thread::sleep(Duration::from_secs(2u64.pow(attempt)));
+                    continue;
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+// Issues a token-authenticated PATCH with a JSON body, retrying on network errors and 5xx
+// responses the same way `get_with_retry` does.
+fn patch_with_retry(client: &Client,
+                     url: &str,
+                     token: &str,
+                     body: &JsonValue)
+                     -> Result<reqwest::Response, ReqwestError> {
+    let mut attempt = 0;
+    loop {
+        let result = client
+            .patch(url)
+            .header("Authorization", format!("Token {}", token))
+            .json(body)
+            .send();
+        match result {
+            Ok(response) => {
+                if response.status().is_server_error() && attempt < MAX_RETRIES {
+                    attempt += 1;
+                    warn!("PATCH {} returned {}, retrying ({}/{})",
+                          url,
+                          response.status(),
+                          attempt,
+                          MAX_RETRIES);
+                    thread::sleep(Duration::from_secs(2u64.pow(attempt)));
+                    continue;
+                }
+                return Ok(response);
+            }
+            Err(err) => {
+                if attempt < MAX_RETRIES {
+                    attempt += 1;
+                    warn!("PATCH {} failed ({}), retrying ({}/{})", url, err, attempt, MAX_RETRIES);
+                    thread::sleep(Duration::from_secs(2u64.pow(attempt)));
+                    continue;
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
 // Macros that helps with declaring API endpoints.
 macro_rules! api_endpoint {
-    ($name:ident, $base:expr, $with_token:expr, $ret:ty) => (
+    ($name:ident, $base:expr, $with_token:expr, $retry:expr, $ret:ty) => (
         pub fn $name(&self, params: &[(&str, Option<&str>)]) -> Result<$ret, TunnelClientError> {
             if $with_token {
                 if self.token.is_none() {
@@ -52,10 +213,7 @@ macro_rules! api_endpoint {
                 }
             }
 
-            let client = Client::new().expect("Client creation failure");
-            match client
-                    .get(&self.get_full_url($base, params, $with_token))
-                    .send() {
+            match get_with_retry(&self.client, &self.get_full_url($base, params, $with_token), $retry) {
                 Ok(mut response) => {
                     if *response.status() == StatusCode::Ok {
                         let res: Result<$ret, ReqwestError> = response.json();
@@ -75,7 +233,7 @@ macro_rules! api_endpoint {
 
 // Special case for empty answers.
 macro_rules! empty_api_endpoint {
-    ($name:ident, $base:expr, $with_token:expr) => (
+    ($name:ident, $base:expr, $with_token:expr, $retry:expr) => (
         pub fn $name(&self, params: &[(&str, Option<&str>)]) -> Result<(), TunnelClientError> {
             if $with_token {
                 if self.token.is_none() {
@@ -84,10 +242,7 @@ macro_rules! empty_api_endpoint {
                 }
             }
 
-            let client = Client::new().expect("Client creation failure");
-            match client
-                    .get(&self.get_full_url($base, params, $with_token))
-                    .send() {
+            match get_with_retry(&self.client, &self.get_full_url($base, params, $with_token), $retry) {
                 Ok(response) => {
                     if *response.status() == StatusCode::Ok {
                         Ok(())
@@ -106,9 +261,29 @@ impl TunnelClient {
         TunnelClient {
             tunnel_url: tunnel_url.to_owned(),
             token: token,
+            client: Client::builder()
+                .timeout(HTTP_TIMEOUT)
+                .build()
+                .expect("Client creation failure"),
+            acme_endpoint: AcmeEndpoint::Production,
+            contact_email: None,
         }
     }
 
+    // Points `lets_encrypt*` at a different ACME directory, e.g. Let's Encrypt's staging
+    // environment, so integrations can be tested without burning into real rate limits.
+    pub fn with_acme_endpoint(mut self, acme_endpoint: AcmeEndpoint) -> Self {
+        self.acme_endpoint = acme_endpoint;
+        self
+    }
+
+    // Sets the contact email passed to ACME account registration. Only used when a fresh
+    // account is created; see `load_or_create_account`.
+    pub fn with_contact_email(mut self, email: &str) -> Self {
+        self.contact_email = Some(email.to_owned());
+        self
+    }
+
     fn get_full_url(&self,
                     path: &str,
                     params: &[(&str, Option<&str>)],
@@ -132,103 +307,512 @@ impl TunnelClient {
         url
     }
 
-    api_endpoint!(call_subscribe, "subscribe", false, NameAndToken);
+    api_endpoint!(call_subscribe, "subscribe", false, false, NameAndToken);
     pub fn subscribe(&self, name: &str, description: Option<&str>) -> Option<Self> {
         match self.call_subscribe(&[("name", Some(name)), ("desc", description)]) {
             Ok(n_t) => {
                 Some(TunnelClient {
                          tunnel_url: self.tunnel_url.clone(),
                          token: Some(n_t.token),
+                         client: self.client.clone(),
+                         acme_endpoint: self.acme_endpoint.clone(),
+                         contact_email: self.contact_email.clone(),
                      })
             }
             Err(_) => None,
         }
     }
 
-    empty_api_endpoint!(call_unsubscribe, "unsubscribe", true);
+    empty_api_endpoint!(call_unsubscribe, "unsubscribe", true, true);
     pub fn unsubscribe(&self) -> Result<(), TunnelClientError> {
         self.call_unsubscribe(&[])
     }
 
-    empty_api_endpoint!(call_register, "register", true);
+    empty_api_endpoint!(call_register, "register", true, true);
     pub fn register(&self, local_ip: &str) -> Result<(), TunnelClientError> {
         self.call_register(&[("local_ip", Some(local_ip))])
     }
 
-    empty_api_endpoint!(call_dnsconfig, "dnsconfig", true);
+    empty_api_endpoint!(call_dnsconfig, "dnsconfig", true, true);
     pub fn dnsconfig(&self, challenge: &str) -> Result<(), TunnelClientError> {
         self.call_dnsconfig(&[("challenge", Some(challenge))])
     }
 
-    api_endpoint!(call_info, "info", true, ServerInfo);
+    api_endpoint!(call_info, "info", true, true, ServerInfo);
     pub fn info(&self) -> Result<ServerInfo, TunnelClientError> {
         self.call_info(&[])
     }
 
-    api_endpoint!(call_ping, "ping", true, Discovered);
+    api_endpoint!(call_ping, "ping", true, true, Discovered);
     pub fn ping(&self) -> Result<Discovered, TunnelClientError> {
         self.call_ping(&[])
     }
 
-    empty_api_endpoint!(call_adddiscovery, "adddiscovery", true);
+    empty_api_endpoint!(call_adddiscovery, "adddiscovery", true, true);
     pub fn adddiscovery(&self, disco: &str) -> Result<(), TunnelClientError> {
         self.call_adddiscovery(&[("disco", Some(disco))])
     }
 
-    empty_api_endpoint!(call_revokediscovery, "adddiscovery", true);
+    empty_api_endpoint!(call_revokediscovery, "adddiscovery", true, true);
     pub fn revokediscovery(&self, disco: &str) -> Result<(), TunnelClientError> {
         self.call_revokediscovery(&[("disco", Some(disco))])
     }
 
-    empty_api_endpoint!(call_setemail, "setemail", true);
+    empty_api_endpoint!(call_setemail, "setemail", true, true);
     pub fn setemail(&self, email: &str) -> Result<(), TunnelClientError> {
         self.call_setemail(&[("email", Some(email))])
     }
 
-    empty_api_endpoint!(call_revokeemail, "revokeemail", true);
+    empty_api_endpoint!(call_revokeemail, "revokeemail", true, true);
     pub fn revokeemail(&self, email: &str) -> Result<(), TunnelClientError> {
         self.call_revokeemail(&[("email", Some(email))])
     }
 
-    // Starts the LE flow.
+    // Loads the ACME account persisted under `path` for the current `acme_endpoint`, or
+    // registers a new one and persists it there when none exists yet. Each endpoint (e.g.
+    // production vs. staging) keeps its own account file, since a registration kid issued by
+    // one ACME server is meaningless to another. A persisted account is reconstructed from its
+    // saved key and registration URL without hitting the ACME server again; `email` is only
+    // used for a fresh registration, since an existing account keeps whatever contact it was
+    // created with.
+    pub fn load_or_create_account(&self,
+                                  directory: &Directory,
+                                  path: &Path,
+                                  email: Option<&str>)
+                                  -> Result<Account, TunnelClientError> {
+        let config_path = path.join(self.acme_endpoint.account_filename());
+
+        if config_path.exists() {
+            let mut contents = String::new();
+            File::open(&config_path)
+                .and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|err| {
+                             TunnelClientError::Other(format!("unable to read {}: {}",
+                                                               config_path.display(),
+                                                               err))
+                         })?;
+            let config: AccountConfig = serde_json::from_str(&contents)
+                .map_err(|err| TunnelClientError::Other(format!("invalid account config: {}", err)))?;
+            let pkey = PKey::private_key_from_pem(config.private_key_pem.as_bytes())
+                .map_err(|err| TunnelClientError::Other(format!("invalid account key: {}", err)))?;
+
+            info!("Reusing existing ACME account from {}", config_path.display());
+            return Ok(Account::from_existing(directory, pkey, &config.registration_url)?);
+        }
+
+        info!("No ACME account found, registering a new one");
+        let mut registration = directory.account_registration();
+        if let Some(email) = email {
+            registration = registration.email(email);
+        }
+        let account = registration.register()?;
+
+        let private_key_pem = account
+            .private_key()
+            .private_key_to_pem_pkcs8()
+            .map_err(|err| TunnelClientError::Other(format!("unable to export account key: {}", err)))?;
+        let serialized = serde_json::to_string(&AccountConfig {
+                                                     private_key_pem:
+                                                         String::from_utf8_lossy(&private_key_pem)
+                                                             .into_owned(),
+                                                     registration_url: account.registration_url().to_owned(),
+                                                 })
+                .map_err(|err| {
+                             TunnelClientError::Other(format!("unable to serialize account config: {}", err))
+                         })?;
+        File::create(&config_path)
+            .and_then(|mut file| file.write_all(serialized.as_bytes()))
+            .map_err(|err| {
+                         TunnelClientError::Other(format!("unable to write {}: {}",
+                                                           config_path.display(),
+                                                           err))
+                     })?;
+
+        Ok(account)
+    }
+
+    // Starts the LE flow using the tunnel server's own dnsconfig endpoint to publish
+    // challenges. This only works for domains hosted by the tunnel service itself; see
+    // `lets_encrypt_with_dns_provider` for other DNS hosts.
     pub fn lets_encrypt(&self,
                         domain: &str,
                         name: &str,
                         path: &Path)
                         -> Result<(), TunnelClientError> {
-        if self.token.is_none() {
-            error!("No token available to retrieve the certificate for {}",
-                   domain);
-            return Err(TunnelClientError::NoToken);
-        }
+        self.lets_encrypt_with_dns_provider(domain, name, path, &TunnelDnsProvider::new(self))
+    }
 
-        let directory = Directory::lets_encrypt()?;
-        let account = directory.account_registration().register()?;
+    // Starts the LE flow, publishing DNS-01 challenges through `dns_provider` instead of
+    // being tied to a single DNS host.
+    pub fn lets_encrypt_with_dns_provider(&self,
+                                          domain: &str,
+                                          name: &str,
+                                          path: &Path,
+                                          dns_provider: &DnsProvider)
+                                          -> Result<(), TunnelClientError> {
+        let directory = self.acme_endpoint.directory()?;
+        let email = self.contact_email.as_ref().map(String::as_str);
+        let account = self.load_or_create_account(&directory, path, email)?;
 
         let remote_domain = format!("{}.box.{}", name, domain);
         let local_domain = format!("local.{}.box.{}", name, domain);
+        let domains = [remote_domain.as_str(), local_domain.as_str()];
+
+        self.complete_dns01_challenges(&account, &domains, dns_provider)?;
+
+        let certificate_signer = account.certificate_signer(&domains);
+        let cert = certificate_signer.sign_certificate()?;
+        cert.save_signed_certificate_and_chain(None, path.join("certificate.pem"))?;
+        cert.save_private_key(path.join("privatekey.pem"))?;
+        info!("Certificate and private key for {} saved.", domain);
+        Ok(())
+    }
 
+    // Same as `lets_encrypt`, but signs a CSR generated from a locally-held private key
+    // instead of letting acme_client generate and discard one on every call. The key is
+    // persisted under `path` so it stays stable across renewals.
+    pub fn lets_encrypt_with_csr(&self,
+                                domain: &str,
+                                name: &str,
+                                path: &Path)
+                                -> Result<(), TunnelClientError> {
+        self.lets_encrypt_with_csr_and_dns_provider(domain, name, path, &TunnelDnsProvider::new(self))
+    }
+
+    // Same as `lets_encrypt_with_dns_provider`, but signs a locally-generated CSR; see
+    // `lets_encrypt_with_csr`.
+    pub fn lets_encrypt_with_csr_and_dns_provider(&self,
+                                                  domain: &str,
+                                                  name: &str,
+                                                  path: &Path,
+                                                  dns_provider: &DnsProvider)
+                                                  -> Result<(), TunnelClientError> {
+        let directory = self.acme_endpoint.directory()?;
+        let email = self.contact_email.as_ref().map(String::as_str);
+        let account = self.load_or_create_account(&directory, path, email)?;
+
+        let remote_domain = format!("{}.box.{}", name, domain);
+        let local_domain = format!("local.{}.box.{}", name, domain);
         let domains = [remote_domain.as_str(), local_domain.as_str()];
 
-        for domain in &domains {
+        self.complete_dns01_challenges(&account, &domains, dns_provider)?;
+
+        let csr_der = load_or_create_csr(path, &domains)?;
+        let certificate_signer = account.certificate_signer(&domains).csr(csr_der);
+        let cert = certificate_signer.sign_certificate()?;
+        cert.save_signed_certificate_and_chain(None, path.join("certificate.pem"))?;
+        info!("Certificate for {} saved, signed with the persisted private key.", domain);
+        Ok(())
+    }
+
+    // Runs the DNS-01 challenge for every entry in `domains` through `dns_provider`, waiting
+    // for propagation before asking the ACME server to validate each one.
+    fn complete_dns01_challenges(&self,
+                                 account: &Account,
+                                 domains: &[&str],
+                                 dns_provider: &DnsProvider)
+                                 -> Result<(), TunnelClientError> {
+        for domain in domains {
             let authorization = account.authorization(domain)?;
             let dns_challenge = match authorization.get_dns_challenge() {
                 Some(challenge) => challenge,
                 None => return Err(TunnelClientError::NoChallenge),
             };
             let signature = dns_challenge.signature()?;
+            let acme_fqdn = format!("_acme-challenge.{}", domain);
 
-            self.dnsconfig(&signature)?;
+            dns_provider.set_txt(&acme_fqdn, &signature)?;
+            let validated = match wait_for_propagation(&acme_fqdn, &signature, DEFAULT_PROPAGATION_TIMEOUT) {
+                Ok(()) => dns_challenge.validate().map_err(TunnelClientError::from),
+                Err(err) => Err(err),
+            };
 
-            dns_challenge.validate()?;
+            // Always clear the published record, whether or not propagation/validation
+            // actually succeeded, so a failed attempt doesn't leave a stale TXT record behind.
+            // A cleanup failure is only logged, not returned, so it can't mask the
+            // propagation/validation error the operator actually needs to see.
+            if let Err(err) = dns_provider.clear_txt(&acme_fqdn) {
+                warn!("Failed to clear {} after DNS-01 challenge: {:?}", acme_fqdn, err);
+            }
+            validated?;
             info!("DNS challenge validated for {}", domain);
         }
+        Ok(())
+    }
 
-        let certificate_signer = account.certificate_signer(&domains);
-        let cert = certificate_signer.sign_certificate()?;
-        cert.save_signed_certificate_and_chain(None, path.join("certificate.pem"))?;
-        cert.save_private_key(path.join("privatekey.pem"))?;
-        info!("Certificate and private key for {} saved.", domain);
+    // Returns the number of whole days before the certificate.pem stored under `path` expires.
+    fn days_until_expiry(path: &Path) -> Result<i64, TunnelClientError> {
+        let pem_data = fs::read(path.join("certificate.pem"))
+            .map_err(|err| TunnelClientError::Certificate(format!("unable to read certificate: {}", err)))?;
+        let (_, pem) = parse_x509_pem(&pem_data)
+            .map_err(|err| TunnelClientError::Certificate(format!("invalid certificate PEM: {}", err)))?;
+        let cert = pem.parse_x509()
+            .map_err(|err| TunnelClientError::Certificate(format!("invalid certificate: {}", err)))?;
+
+        let not_after = cert.validity().not_after.to_datetime();
+        Ok((not_after - OffsetDateTime::now_utc()).whole_days())
+    }
+
+    // Renews the certificate for `domain`/`name` if none exists yet under `path`, or if the
+    // existing one expires within `renew_before_days`, publishing challenges through
+    // `dns_provider`. Returns `true` if a renewal happened.
+    pub fn renew_if_needed(&self,
+                           domain: &str,
+                           name: &str,
+                           path: &Path,
+                           renew_before_days: i64,
+                           dns_provider: &DnsProvider)
+                           -> Result<bool, TunnelClientError> {
+        if !path.join("certificate.pem").exists() {
+            info!("No certificate found for {}, requesting one", domain);
+            self.lets_encrypt_with_csr_and_dns_provider(domain, name, path, dns_provider)?;
+            return Ok(true);
+        }
+
+        let days_left = Self::days_until_expiry(path)?;
+        if days_left < renew_before_days {
+            info!("Certificate for {} expires in {} day(s), renewing", domain, days_left);
+            self.lets_encrypt_with_csr_and_dns_provider(domain, name, path, dns_provider)?;
+            Ok(true)
+        } else {
+            info!("Certificate for {} is still valid for {} day(s), skipping renewal",
+                  domain,
+                  days_left);
+            Ok(false)
+        }
+    }
+
+    // Runs `renew_if_needed` forever, sleeping `check_interval` between checks. A failed
+    // renewal attempt (e.g. a transient ACME error) is logged and retried on the next tick
+    // rather than aborting the loop.
+    pub fn run_renewal_loop(&self,
+                            domain: &str,
+                            name: &str,
+                            path: &Path,
+                            renew_before_days: i64,
+                            check_interval: Duration,
+                            dns_provider: &DnsProvider)
+                            -> ! {
+        loop {
+            match self.renew_if_needed(domain, name, path, renew_before_days, dns_provider) {
+                Ok(true) => info!("Certificate for {} renewed", domain),
+                Ok(false) => {}
+                Err(err) => {
+                    error!("Renewal check for {} failed, will retry next tick: {:?}",
+                           domain,
+                           err)
+                }
+            }
+
+            thread::sleep(check_interval);
+        }
+    }
+}
+
+/// Publishes and clears the `_acme-challenge` TXT record used to prove control of a domain
+/// during the ACME DNS-01 flow. Lets `lets_encrypt_with_dns_provider` and
+/// `lets_encrypt_with_csr_and_dns_provider` work against any DNS host, not just the one the
+/// tunnel server controls.
+pub trait DnsProvider {
+    fn set_txt(&self, fqdn: &str, value: &str) -> Result<(), TunnelClientError>;
+    fn clear_txt(&self, fqdn: &str) -> Result<(), TunnelClientError>;
+}
+
+/// Publishes the challenge through the tunnel server's own `dnsconfig` endpoint. This is the
+/// only provider that works for domains hosted by the tunnel service itself.
+pub struct TunnelDnsProvider<'a> {
+    client: &'a TunnelClient,
+}
+
+impl<'a> TunnelDnsProvider<'a> {
+    pub fn new(client: &'a TunnelClient) -> Self {
+        TunnelDnsProvider { client: client }
+    }
+}
+
+impl<'a> DnsProvider for TunnelDnsProvider<'a> {
+    fn set_txt(&self, _fqdn: &str, value: &str) -> Result<(), TunnelClientError> {
+        self.client.dnsconfig(value)
+    }
+
+    fn clear_txt(&self, _fqdn: &str) -> Result<(), TunnelClientError> {
+        // The tunnel server only ever remembers the latest challenge value, so there is
+        // nothing to clean up once validation has run.
         Ok(())
     }
 }
+
+/// Publishes the challenge to a domain hosted on deSEC (https://desec.io) by PATCHing its
+/// RRset API directly.
+pub struct DesecProvider {
+    token: String,
+    zone: String,
+    client: Client,
+}
+
+impl DesecProvider {
+    /// `zone` is the domain registered with deSEC, e.g. `example.com`.
+    pub fn new(token: &str, zone: &str) -> Self {
+        DesecProvider {
+            token: token.to_owned(),
+            zone: zone.to_owned(),
+            client: Client::builder()
+                .timeout(HTTP_TIMEOUT)
+                .build()
+                .expect("Client creation failure"),
+        }
+    }
+
+    fn subname_for(&self, fqdn: &str) -> Result<String, TunnelClientError> {
+        if fqdn == self.zone {
+            return Ok(String::new());
+        }
+
+        let suffix = format!(".{}", self.zone);
+        if fqdn.ends_with(&suffix) {
+            Ok(fqdn[..fqdn.len() - suffix.len()].to_owned())
+        } else {
+            Err(TunnelClientError::Other(format!("{} is not part of the {} zone", fqdn, self.zone)))
+        }
+    }
+
+    fn upsert(&self, fqdn: &str, records: &[String]) -> Result<(), TunnelClientError> {
+        let subname = self.subname_for(fqdn)?;
+        let url = format!("https://desec.io/api/v1/domains/{}/rrsets/", self.zone);
+        let body = json!([{
+                              "subname": subname,
+                              "type": "TXT",
+                              "ttl": 3600,
+                              "records": records,
+                          }]);
+
+        let response = patch_with_retry(&self.client, &url, &self.token, &body)?;
+
+        if *response.status() == StatusCode::Ok {
+            Ok(())
+        } else {
+            Err(TunnelClientError::BadRequest)
+        }
+    }
+}
+
+impl DnsProvider for DesecProvider {
+    fn set_txt(&self, fqdn: &str, value: &str) -> Result<(), TunnelClientError> {
+        self.upsert(fqdn, &[format!("\"{}\"", value)])
+    }
+
+    fn clear_txt(&self, fqdn: &str) -> Result<(), TunnelClientError> {
+        self.upsert(fqdn, &[])
+    }
+}
+
+// Loads the private key persisted under `path` (generating one on first use) and returns a
+// freshly built CSR for `domains`, so the same key survives renewals. Kept under its own
+// filename, distinct from the `privatekey.pem` that `lets_encrypt`/`lets_encrypt_with_dns_provider`
+// write via acme_client: that key is serialized by a different library and isn't guaranteed to
+// be a PEM `KeyPair::from_pem` can parse back.
+fn load_or_create_csr(path: &Path, domains: &[&str]) -> Result<Vec<u8>, TunnelClientError> {
+    let key_path = path.join("csr-privatekey.pem");
+
+    let key_is_new = !key_path.exists();
+    let key_pair = if key_is_new {
+        KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+            .map_err(|err| TunnelClientError::Other(format!("unable to generate private key: {}", err)))?
+    } else {
+        let pem = fs::read_to_string(&key_path)
+            .map_err(|err| TunnelClientError::Other(format!("unable to read {}: {}", key_path.display(), err)))?;
+        KeyPair::from_pem(&pem)
+            .map_err(|err| TunnelClientError::Other(format!("invalid private key in {}: {}", key_path.display(), err)))?
+    };
+
+    if key_is_new {
+        fs::write(&key_path, key_pair.serialize_pem())
+            .map_err(|err| TunnelClientError::Other(format!("unable to write {}: {}", key_path.display(), err)))?;
+    }
+
+    let params = CertificateParams::new(domains.iter().map(|domain| domain.to_string()).collect::<Vec<_>>())
+        .map_err(|err| TunnelClientError::Other(format!("invalid domain name for CSR: {}", err)))?;
+
+    let csr = params
+        .serialize_request(&key_pair)
+        .map_err(|err| TunnelClientError::Other(format!("unable to build CSR: {}", err)))?;
+
+    Ok(csr.der().to_vec())
+}
+
+// Builds a resolver that queries `fqdn`'s authoritative nameservers directly, bypassing any
+// recursive resolver cache that might still be serving a stale (pre-update) answer.
+fn authoritative_resolver(fqdn: &str) -> Result<Resolver, TunnelClientError> {
+    let system_resolver = Resolver::from_system_conf()
+        .map_err(|err| TunnelClientError::Other(format!("unable to build system resolver: {}", err)))?;
+
+    // A challenge name rarely owns NS records itself, so walk up the labels until we reach
+    // the zone cut that does.
+    let mut candidate = fqdn;
+    let ns_records = loop {
+        match system_resolver.lookup(candidate, RecordType::NS) {
+            Ok(response) => break response,
+            Err(_) if candidate.contains('.') => {
+                candidate = &candidate[candidate.find('.').unwrap() + 1..];
+            }
+            Err(err) => {
+                return Err(TunnelClientError::Other(format!("unable to find authoritative servers for {}: {}",
+                                                              fqdn,
+                                                              err)))
+            }
+        }
+    };
+
+    let mut nameservers = NameServerConfigGroup::new();
+    for ns in ns_records.iter() {
+        if let Ok(ips) = system_resolver.lookup_ip(ns.to_string().as_str()) {
+            for ip in ips.iter() {
+                nameservers.push(NameServerConfig {
+                                      socket_addr: SocketAddr::new(ip, 53),
+                                      protocol: Protocol::Udp,
+                                      tls_dns_name: None,
+                                      trust_negative_responses: false,
+                                      bind_addr: None,
+                                  });
+            }
+        }
+    }
+
+    Resolver::new(ResolverConfig::from_parts(None, vec![], nameservers), ResolverOpts::default())
+        .map_err(|err| TunnelClientError::Other(format!("unable to build authoritative resolver: {}", err)))
+}
+
+// Polls `fqdn`'s authoritative nameservers until a TXT record equal to `expected` shows up,
+// using capped exponential backoff between attempts. Returns `PropagationTimeout` if
+// `total_timeout` elapses first.
+fn wait_for_propagation(fqdn: &str, expected: &str, total_timeout: Duration) -> Result<(), TunnelClientError> {
+    let resolver = authoritative_resolver(fqdn)?;
+    let start = SystemTime::now();
+    let mut delay = Duration::from_secs(2);
+
+    loop {
+        match resolver.txt_lookup(fqdn) {
+            Ok(response) => {
+                let propagated = response
+                    .iter()
+                    .any(|txt| txt.txt_data().iter().any(|chunk| chunk.as_ref() == expected.as_bytes()));
+                if propagated {
+                    info!("DNS propagation confirmed for {}", fqdn);
+                    return Ok(());
+                }
+                info!("TXT record for {} not yet propagated, retrying in {:?}", fqdn, delay);
+            }
+            Err(err) => info!("TXT lookup for {} failed ({}), retrying in {:?}", fqdn, err, delay),
+        }
+
+        let elapsed = start.elapsed().unwrap_or(total_timeout);
+        if elapsed >= total_timeout {
+            return Err(TunnelClientError::PropagationTimeout);
+        }
+
+        // Cap the sleep to whatever remains of the budget so the loop always gets one last
+        // poll right at the deadline, instead of bailing a whole `delay` early.
+        thread::sleep(cmp::min(delay, total_timeout - elapsed));
+        delay = cmp::min(delay * 2, Duration::from_secs(30));
+    }
+}